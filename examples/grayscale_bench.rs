@@ -0,0 +1,42 @@
+// Grayscale conversion timing, single-threaded vs parallel
+// Run with: cargo run --release --example grayscale_bench
+use image::{DynamicImage, RgbImage, RgbaImage};
+use rusty_bandwidth::{convert_to_grayscale_optimized, DEFAULT_LUMA_WEIGHTS};
+use std::time::{Duration, Instant};
+
+const WIDTH: u32 = 4000;
+const HEIGHT: u32 = 3000;
+const RUNS: u32 = 10;
+
+// Average time of a conversion over RUNS, after one warm-up run
+fn time_conversion(img: &DynamicImage) -> Duration {
+    convert_to_grayscale_optimized(img, DEFAULT_LUMA_WEIGHTS, false, false).unwrap();
+    let started = Instant::now();
+    for _ in 0..RUNS {
+        convert_to_grayscale_optimized(img, DEFAULT_LUMA_WEIGHTS, false, false).unwrap();
+    }
+    started.elapsed() / RUNS
+}
+
+fn main() {
+    // Deterministic noise-like pattern so every pixel differs
+    let rgb = RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        image::Rgb([(x * 7 + y * 3) as u8, ((x * 13) ^ (y * 5)) as u8, (x + y * 11) as u8])
+    });
+    let rgba = RgbaImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let pixel = rgb.get_pixel(x, y);
+        image::Rgba([pixel[0], pixel[1], pixel[2], (x ^ y) as u8])
+    });
+    let images = [("RGB", DynamicImage::ImageRgb8(rgb)), ("RGBA", DynamicImage::ImageRgba8(rgba))];
+
+    let single_thread = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    println!("{}x{} image, average of {} runs, {} threads", WIDTH, HEIGHT, RUNS, rayon::current_num_threads());
+    for (name, img) in &images {
+        let single = single_thread.install(|| time_conversion(img));
+        let parallel = time_conversion(img);
+        let same_output = single_thread.install(|| convert_to_grayscale_optimized(img, DEFAULT_LUMA_WEIGHTS, false, false).unwrap())
+            == convert_to_grayscale_optimized(img, DEFAULT_LUMA_WEIGHTS, false, false).unwrap();
+        println!("{:>4}: single-threaded {:>8.2?}, parallel {:>8.2?} ({:.1}x), identical output: {}",
+            name, single, parallel, single.as_secs_f64() / parallel.as_secs_f64(), same_output);
+    }
+}
@@ -0,0 +1,1754 @@
+// Image pipeline shared by the proxy binary: query parsing, grayscale conversion and encoding
+// Kept free of server state so each piece can be used and tested without a running server
+use bytes::Bytes;
+use image::{imageops, DynamicImage, ImageBuffer, ImageFormat, GenericImageView, Pixel, Primitive, RgbaImage};
+use image::metadata::Orientation;
+use image::codecs::avif::AvifEncoder;
+use jpegxl_rs::{encoder_builder, EncodeError, encode::EncoderFrame, encode::EncoderSpeed, encode::EncoderResult, encode::JxlEncoder};
+use percent_encoding::percent_decode_str;
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::sync::OnceLock;
+use tracing::debug;
+
+// Output image formats the proxy can produce
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OutputFormat {
+    WebP,
+    Jxl,
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "WebP",
+            OutputFormat::Jxl => "JXL",
+            OutputFormat::Avif => "AVIF",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Jxl => "image/jxl",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+}
+
+// How the encoder may treat pixel values
+// NearLossless is a WebP mode that lets pixel values move slightly for much smaller files
+// than Lossless; JXL and AVIF have no such mode and encode it like Lossy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    Lossy,
+    NearLossless,
+    Lossless,
+}
+
+impl Compression {
+    // Value of the lossless= parameter that selects it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Lossy => "0",
+            Compression::NearLossless => "near",
+            Compression::Lossless => "1",
+        }
+    }
+}
+
+// How w and h are applied when both are given
+// Contain fits the image inside the box, Cover fills it and center-crops the overflow,
+// Fill stretches to exactly that size
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Fit {
+    Contain,
+    Cover,
+    Fill,
+}
+
+impl Fit {
+    // Value of the fit= parameter that selects it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+// Parameters extracted from the URL query string
+// Serializable so --isolated-workers can hand them to a worker process
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImageParams {
+    pub url: String,
+    pub quality: u8,      // 1-100, where 100 is highest quality
+    pub quality_set: bool, // Whether the client passed quality explicitly
+    pub grayscale: bool,  // Convert to black and white if true
+    pub tone: Option<Tone>, // Sepia or tinted monochrome, instead of plain grayscale
+    pub brightness: i8,     // -100 (black) to 100 (white), 0 leaves the image alone
+    pub contrast: i8,       // -100 (flat gray) to 100 (4x contrast), 0 leaves the image alone
+    pub width: Option<u32>,  // Target width in pixels
+    pub height: Option<u32>, // Target height in pixels
+    pub fit: Fit,            // How width and height together are applied
+    #[serde(serialize_with = "serialize_resize_filter", deserialize_with = "deserialize_resize_filter")]
+    pub filter: Option<imageops::FilterType>, // Resampling filter picked by the client, otherwise the server picks
+    pub allow_upscale: bool, // Let w/h enlarge the image instead of stopping at the source size
+    pub dpr: Option<f32>,    // Device pixel ratio the w/h were scaled by, echoed in X-DPR
+    pub smart_crop: bool,    // Crop to w x h around the most detailed region
+    pub passthrough: bool,   // Return the original bytes without processing
+    pub luma_weights: [u32; 3], // R, G, B weights for grayscale, in thousandths
+    pub target_size: Option<u32>, // Lower the quality until the output fits in this many bytes
+    pub linear_light: bool, // Gamma-correct grayscale, weighting channels in linear light
+    pub dither: bool,       // Dither 8-bit grayscale luma instead of truncating it
+    pub format: Option<OutputFormat>, // Output format picked by the client, overriding negotiation
+    pub compression: Option<Compression>, // Picked by the client with lossless=, otherwise by the server
+    pub effort: Option<u8>, // WebP method 0-6 for this request, instead of the server's
+    pub sharpen: u8,        // Unsharp mask strength 0-100 after resizing, 0 is off
+    pub keep_metadata: bool, // Send original bytes with their EXIF/XMP instead of stripping it
+    pub crop: Option<[u32; 4]>, // x, y, width, height of the region to keep, cut before resizing
+}
+
+// Largest width or height a client may ask for
+pub const MAX_REQUESTED_DIMENSION: u32 = 20000;
+
+// Parse a w/h value, ignoring zero and garbage and clamping absurd sizes
+pub fn parse_dimension(value: &str) -> Option<u32> {
+    value.parse::<u32>()
+        .ok()
+        .filter(|&size| size > 0)
+        .map(|size| size.min(MAX_REQUESTED_DIMENSION))
+}
+
+// Parse crop=x,y,w,h into a region in source pixels
+// Whether it fits the image is only known after decoding, here it just has to be well-formed
+pub fn parse_crop(value: &str) -> Result<[u32; 4], String> {
+    let invalid = || format!("Invalid crop '{}': expected x,y,w,h in pixels with a positive width and height", value);
+    let numbers: Vec<u32> = value
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    match numbers[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok([x, y, width, height]),
+        _ => Err(invalid()),
+    }
+}
+
+// Highest WebP method (effort); 6 compresses best and takes the longest
+pub const MAX_WEBP_METHOD: u8 = 6;
+
+// AVIF encoder speed unless configured; the slower settings take seconds per photo
+pub const DEFAULT_AVIF_SPEED: u8 = 8;
+
+// JXL distance for quality l is 8 * (1 - (l / 100)^exponent); below 1 the curve keeps
+// low l values closer to visually lossless than a straight line would
+pub const DEFAULT_JXL_CURVE_EXPONENT: f32 = 0.7;
+
+// Quality from which JXL output is lossless
+pub const DEFAULT_JXL_LOSSLESS_THRESHOLD: u8 = 95;
+
+// Rec.601 luma weights, the grayscale default
+pub const DEFAULT_LUMA_WEIGHTS: [u32; 3] = [299, 587, 114];
+
+// Rec.709 luma weights (0.2126, 0.7152, 0.0722 rounded to thousandths, still summing to 1000)
+pub const REC709_LUMA_WEIGHTS: [u32; 3] = [213, 715, 72];
+
+// Parse coeff=601|709 into one of the standard grayscale weight sets
+pub fn parse_luma_standard(value: &str) -> Result<[u32; 3], String> {
+    match value {
+        "601" => Ok(DEFAULT_LUMA_WEIGHTS),
+        "709" => Ok(REC709_LUMA_WEIGHTS),
+        _ => Err(format!("Invalid coeff '{}': expected 601 or 709", value)),
+    }
+}
+
+// Parse lumacoef=r,g,b into grayscale weights
+// Each coefficient must be a non-negative number and together they must sum to ~1.0
+pub fn parse_luma_coefficients(value: &str) -> Result<[u32; 3], String> {
+    let invalid = || format!("Invalid lumacoef '{}': expected three non-negative numbers summing to 1.0, e.g. 0.2126,0.7152,0.0722", value);
+    let coefficients: Vec<f64> = value
+        .split(',')
+        .map(|part| part.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    if coefficients.len() != 3 || coefficients.iter().any(|c| !c.is_finite() || *c < 0.0) {
+        return Err(invalid());
+    }
+    if (coefficients.iter().sum::<f64>() - 1.0).abs() > 0.01 {
+        return Err(invalid());
+    }
+    Ok([
+        (coefficients[0] * 1000.0).round() as u32,
+        (coefficients[1] * 1000.0).round() as u32,
+        (coefficients[2] * 1000.0).round() as u32,
+    ])
+}
+
+// Short usage hint for requests that don't say which image to fetch
+pub const USAGE: &str = "Use /?url=<image_url>&bw=<0|1>&l=<1-100>";
+
+// Query parameters the proxy understands, everything else after url= belongs to the image URL
+pub const CONTROL_PARAMS: &[&str] = &["url", "l", "bw", "w", "h", "smartcrop", "passthrough", "lumacoef", "variant", "size", "coeff", "linear", "format", "lossless", "effort", "sharpen", "keepmeta", "crop", "fit", "filter", "allow_upscale", "dpr", "tone", "brightness", "contrast", "dither"];
+
+// Parse a format value (webp, jxl or avif, any case)
+pub fn parse_output_format(value: &str) -> Result<OutputFormat, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "webp" => Ok(OutputFormat::WebP),
+        "jxl" => Ok(OutputFormat::Jxl),
+        "avif" => Ok(OutputFormat::Avif),
+        _ => Err(format!("Invalid format '{}': expected webp, jxl or avif", value)),
+    }
+}
+
+// Parse a fit value (contain, cover or fill, any case)
+pub fn parse_fit(value: &str) -> Result<Fit, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "contain" => Ok(Fit::Contain),
+        "cover" => Ok(Fit::Cover),
+        "fill" => Ok(Fit::Fill),
+        _ => Err(format!("Invalid fit '{}': expected contain, cover or fill", value)),
+    }
+}
+
+// Parse a brightness or contrast value, -100 to 100
+pub fn parse_adjustment(key: &str, value: &str) -> Result<i8, String> {
+    value.parse::<i8>().ok()
+        .filter(|amount| (-100..=100).contains(amount))
+        .ok_or_else(|| format!("Invalid {} '{}': {} must be a number from -100 to 100", key, value, key))
+}
+
+// Parse a resize filter name (lanczos3, triangle, catmullrom, nearest or gaussian, any case)
+pub fn parse_resize_filter(value: &str) -> Result<imageops::FilterType, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "lanczos3" => Ok(imageops::FilterType::Lanczos3),
+        "triangle" => Ok(imageops::FilterType::Triangle),
+        "catmullrom" => Ok(imageops::FilterType::CatmullRom),
+        "nearest" => Ok(imageops::FilterType::Nearest),
+        "gaussian" => Ok(imageops::FilterType::Gaussian),
+        _ => Err(format!("Invalid filter '{}': expected lanczos3, triangle, catmullrom, nearest or gaussian", value)),
+    }
+}
+
+// Name parse_resize_filter accepts for a filter
+pub fn resize_filter_name(filter: imageops::FilterType) -> &'static str {
+    match filter {
+        imageops::FilterType::Lanczos3 => "lanczos3",
+        imageops::FilterType::Triangle => "triangle",
+        imageops::FilterType::CatmullRom => "catmullrom",
+        imageops::FilterType::Nearest => "nearest",
+        imageops::FilterType::Gaussian => "gaussian",
+    }
+}
+
+// FilterType has no serde support, so ImageParams carries it by name
+fn serialize_resize_filter<S: Serializer>(filter: &Option<imageops::FilterType>, serializer: S) -> Result<S::Ok, S::Error> {
+    filter.map(resize_filter_name).serialize(serializer)
+}
+
+fn deserialize_resize_filter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<imageops::FilterType>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|name| parse_resize_filter(&name).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+// Parse a lossless value: 1 (lossless), near (near-lossless) or 0 (lossy)
+pub fn parse_compression(value: &str) -> Result<Compression, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "0" => Ok(Compression::Lossy),
+        "near" => Ok(Compression::NearLossless),
+        "1" => Ok(Compression::Lossless),
+        _ => Err(format!("Invalid lossless '{}': expected 0, 1 or near", value)),
+    }
+}
+
+// Range of dpr values accepted; beyond 4x no screen can show the difference
+pub const MIN_DPR: f32 = 0.5;
+pub const MAX_DPR: f32 = 4.0;
+
+// Parse a dpr value (device pixel ratio, e.g. 2 or 1.5)
+pub fn parse_dpr(value: &str) -> Result<f32, String> {
+    value.parse::<f32>().ok()
+        .filter(|dpr| (MIN_DPR..=MAX_DPR).contains(dpr))
+        .ok_or_else(|| format!("Invalid dpr '{}': dpr must be a number from {} to {}", value, MIN_DPR, MAX_DPR))
+}
+
+// Parse a variant value (1x, 2x or 3x, optionally written @2x) into its density
+pub fn parse_variant(value: &str) -> Result<u32, String> {
+    match value.strip_prefix('@').unwrap_or(value) {
+        "1x" => Ok(1),
+        "2x" => Ok(2),
+        "3x" => Ok(3),
+        _ => Err(format!("Invalid variant '{}': expected 1x, 2x or 3x", value)),
+    }
+}
+
+// Parse query parameters from the URL
+// Example URL: /?url=https://example.com/image.jpg&l=80&bw=1
+// The image URL should be percent-encoded, but an unencoded one with its own query string
+// (/?url=https://cdn/img.jpg?sig=abc&exp=123&l=50) is kept intact up to the next control parameter
+// An encoded URL can't contain a raw &, so unknown parameters after it (jpeg=1) are ignored
+// default_quality and default_grayscale apply when the query has no `l` or `bw`
+pub fn parse_query(query: &str, default_quality: u8, default_grayscale: bool) -> Result<ImageParams, String> {
+    let mut params: Vec<(&str, String)> = Vec::new();
+    for pair in query.split('&') {
+        let key = pair.split('=').next().unwrap_or_default();
+        if let Some((last_key, url)) = params.last_mut() {
+            if *last_key == "url" && url.contains("://") && !CONTROL_PARAMS.contains(&key) {
+                url.push('&');
+                url.push_str(pair);
+                continue;
+            }
+        }
+        if let Some((key, value)) = pair.split_once('=') {
+            params.push((key, value.to_string()));
+        }
+    }
+
+    let mut image_params = ImageParams {
+        url: String::new(),
+        quality: default_quality,
+        quality_set: false,
+        grayscale: default_grayscale,
+        tone: None,
+        brightness: 0,
+        contrast: 0,
+        width: None,
+        height: None,
+        fit: Fit::Contain,
+        filter: None,
+        allow_upscale: false,
+        dpr: None,
+        smart_crop: false,
+        passthrough: false,
+        luma_weights: DEFAULT_LUMA_WEIGHTS,
+        target_size: None,
+        linear_light: false,
+        dither: false,
+        format: None,
+        compression: None,
+        effort: None,
+        sharpen: 0,
+        keep_metadata: false,
+        crop: None,
+    };
+    let mut density = 1;
+    let mut luma_standard = None;
+    let mut custom_luma = false;
+
+    for (key, value) in &params {
+        let value = value.as_str();
+        match *key {
+            // The URL of the image to process
+            // A literal :// means it was sent unencoded, and decoding it would mangle its own escapes
+            "url" if value.contains("://") => image_params.url = value.to_string(),
+            "url" => image_params.url = percent_decode_str(value).decode_utf8_lossy().to_string(),
+            // Quality level (l for legacy reasons)
+            // 0 can't produce a usable image so it means the lowest quality, anything above 100 means 100
+            "l" => {
+                let parsed_quality: u32 = value.parse()
+                    .map_err(|_| format!("Invalid quality '{}': l must be a number from 1 to 100", value))?;
+                image_params.quality = parsed_quality.clamp(1, 100) as u8;
+                image_params.quality_set = true;
+            },
+            // Black and white mode (bw=0 means color, bw=1 means grayscale)
+            "bw" => image_params.grayscale = value != "0",
+            // Color tone instead of grayscale (tone=sepia or tone=tint:RRGGBB)
+            "tone" => image_params.tone = Some(parse_tone(&percent_decode_str(value).decode_utf8_lossy())?),
+            // Brightness and contrast adjustments, -100 to 100 (brightness=-20&contrast=10)
+            "brightness" | "contrast" => {
+                let amount = parse_adjustment(key, value)?;
+                if *key == "brightness" {
+                    image_params.brightness = amount;
+                } else {
+                    image_params.contrast = amount;
+                }
+            },
+            // Target dimensions (0 means "not set", absurd values are clamped)
+            "w" => image_params.width = parse_dimension(value),
+            "h" => image_params.height = parse_dimension(value),
+            // How w and h together are applied (fit=contain, cover or fill)
+            "fit" => image_params.fit = parse_fit(value)?,
+            // Resampling filter for w/h (filter=lanczos3, triangle, catmullrom, nearest or gaussian)
+            "filter" => image_params.filter = Some(parse_resize_filter(value)?),
+            // Enlarge images smaller than w/h (allow_upscale=1), by default they keep their size
+            "allow_upscale" => image_params.allow_upscale = value != "0",
+            // Subject-aware cropping (smartcrop=1 together with w and h)
+            "smartcrop" => image_params.smart_crop = value != "0",
+            // Debug mode that skips decoding/encoding (needs --allow-passthrough)
+            "passthrough" => image_params.passthrough = value != "0",
+            // Custom grayscale weights for R, G and B (lumacoef=0.2126,0.7152,0.0722)
+            "lumacoef" => {
+                image_params.luma_weights = parse_luma_coefficients(&percent_decode_str(value).decode_utf8_lossy())?;
+                custom_luma = true;
+            },
+            // Standard grayscale weights, Rec.601 (coeff=601, the default) or Rec.709 (coeff=709)
+            "coeff" => luma_standard = Some(parse_luma_standard(value)?),
+            // Gamma-correct grayscale (linear=1), slower but keeps midtones from darkening
+            "linear" => image_params.linear_light = value != "0",
+            // Dithered grayscale (dither=1), breaks up banding in smooth gradients
+            "dither" => image_params.dither = value != "0",
+            // Output format for this request (format=webp, jxl or avif)
+            "format" => image_params.format = Some(parse_output_format(value)?),
+            // WebP compression effort, 0 (fastest) to 6 (best compression)
+            "effort" => {
+                let effort: u8 = value.parse().ok().filter(|&effort| effort <= MAX_WEBP_METHOD)
+                    .ok_or_else(|| format!("Invalid effort '{}': effort must be a number from 0 to 6", value))?;
+                image_params.effort = Some(effort);
+            },
+            // Sharpening after a resize, 0 (off) to 100 (strongest)
+            "sharpen" => {
+                image_params.sharpen = value.parse().ok().filter(|&sharpen| sharpen <= 100)
+                    .ok_or_else(|| format!("Invalid sharpen '{}': sharpen must be a number from 0 to 100", value))?;
+            },
+            // Keep EXIF, XMP and comments when the original image is sent (keepmeta=1)
+            "keepmeta" => image_params.keep_metadata = value != "0",
+            // Region of the original to keep before resizing (crop=x,y,w,h in source pixels)
+            "crop" => image_params.crop = Some(parse_crop(&percent_decode_str(value).decode_utf8_lossy())?),
+            // Lossless (lossless=1), near-lossless WebP (lossless=near) or lossy (lossless=0) encoding
+            "lossless" => image_params.compression = Some(parse_compression(value)?),
+            // Pixel density multiplier for w/h (variant=2x, Apple-style @2x also works)
+            "variant" => density = parse_variant(&percent_decode_str(value).decode_utf8_lossy())?,
+            // Device pixel ratio for w/h (dpr=2), the fractional form of variant
+            "dpr" => image_params.dpr = Some(parse_dpr(value)?),
+            // Output size limit in KB, met by lowering the quality (size=50)
+            "size" => {
+                let kilobytes: u32 = value.parse().ok().filter(|&kilobytes| kilobytes > 0)
+                    .ok_or_else(|| format!("Invalid size '{}': size must be a positive number of KB", value))?;
+                image_params.target_size = Some(kilobytes.saturating_mul(1024));
+            },
+            _ => {}
+        }
+    }
+
+    // Explicit lumacoef weights win over a coeff standard, whatever their order
+    if let Some(weights) = luma_standard.filter(|_| !custom_luma) {
+        image_params.luma_weights = weights;
+    }
+
+    // w/h are given in CSS pixels, the variant and dpr scale them to device pixels
+    let scale = density as f32 * image_params.dpr.unwrap_or(1.0);
+    let to_device_pixels = |size: u32| ((size as f32 * scale).round() as u32).clamp(1, MAX_REQUESTED_DIMENSION);
+    image_params.width = image_params.width.map(to_device_pixels);
+    image_params.height = image_params.height.map(to_device_pixels);
+
+    Ok(image_params)
+}
+
+// Convert an image to grayscale while preserving alpha channels
+// Weights are per channel (R, G, B) and normalized by their sum
+// With linear_light the weighted sum is taken in linear light and converted back to sRGB,
+// otherwise directly on the sRGB values (faster, but darkens saturated midtones)
+// With dither, 8-bit sources get their luma computed at 16 bits and ordered-dithered back to 8:
+// rounding every pixel to a whole level turns a gentle gradient into flat bands, dithering
+// keeps the fraction as a fine pattern. 16-bit sources keep their precision either way
+// Color types this doesn't know (newer image crate variants) are an error rather than a guess
+pub fn convert_to_grayscale_optimized(img: &DynamicImage, weights: [u32; 3], linear_light: bool, dither: bool) -> Result<DynamicImage, String> {
+    let luma = Luma::new(weights, linear_light);
+    let unsupported = || format!("Unsupported color type for grayscale conversion: {:?}", img.color());
+    if dither && !is_high_bit_depth(img) {
+        let gray16 = map_colors(&DynamicImage::ImageRgba16(img.to_rgba16()), |pixel| [luma.of8(pixel); 3], |pixel| [luma.of16(pixel); 3])
+            .ok_or_else(unsupported)?;
+        return to_8bit_rgb(&gray16).ok_or_else(unsupported);
+    }
+    map_colors(img, |pixel| [luma.of8(pixel); 3], |pixel| [luma.of16(pixel); 3])
+        .ok_or_else(unsupported)
+}
+
+// Color tones for tone=, applied instead of plain grayscale
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Tone {
+    Sepia,          // The classic sepia matrix over the color channels
+    Tint([u8; 3]),  // Grayscale luma multiplied by this sRGB color
+}
+
+impl Tone {
+    // Value of the tone= parameter that selects it
+    pub fn value(&self) -> String {
+        match self {
+            Tone::Sepia => "sepia".to_string(),
+            Tone::Tint([r, g, b]) => format!("tint:{:02x}{:02x}{:02x}", r, g, b),
+        }
+    }
+}
+
+// Parse a tone value: sepia or tint:RRGGBB (hex, any case)
+pub fn parse_tone(value: &str) -> Result<Tone, String> {
+    let invalid = || format!("Invalid tone '{}': expected sepia or tint:RRGGBB", value);
+    let lowercase = value.to_ascii_lowercase();
+    if lowercase == "sepia" {
+        return Ok(Tone::Sepia);
+    }
+    let hex = lowercase.strip_prefix("tint:").ok_or_else(invalid)?;
+    if hex.len() != 6 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok(Tone::Tint([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+// Shift the brightness and scale the contrast around mid-gray, in that order, keeping alpha
+// Brightness moves every channel by brightness% of the full range (100 is white); contrast
+// scales the distance from mid-gray by ((100 + contrast) / 100)^2 like image's adjust_contrast,
+// but on 16-bit images too, where image's brighten would add its value unscaled
+pub fn adjust_brightness_contrast(img: &DynamicImage, brightness: i8, contrast: i8) -> Result<DynamicImage, String> {
+    let factor = ((100.0 + contrast as f32) / 100.0).powi(2);
+    let curve = |value: f32| (((value + brightness as f32 / 100.0).clamp(0.0, 1.0) - 0.5) * factor + 0.5).clamp(0.0, 1.0);
+    let lut8: Vec<u8> = (0..=u8::MAX).map(|value| (curve(value as f32 / 255.0) * 255.0).round() as u8).collect();
+    let lut16: Vec<u16> = if is_high_bit_depth(img) {
+        (0..=u16::MAX).map(|value| (curve(value as f32 / 65535.0) * 65535.0).round() as u16).collect()
+    } else {
+        Vec::new()
+    };
+    map_colors(img, |pixel| pixel.map(|value| lut8[value as usize]), |pixel| pixel.map(|value| lut16[value as usize]))
+        .ok_or_else(|| format!("Unsupported color type for brightness/contrast: {:?}", img.color()))
+}
+
+// Sepia matrix rows for R, G and B output, in thousandths of each input channel
+const SEPIA_MATRIX: [[u32; 3]; 3] = [[393, 769, 189], [349, 686, 168], [272, 534, 131]];
+
+// Apply a tone, keeping alpha like grayscale does
+// Tint takes its luma with the same weights and linear_light option as grayscale
+pub fn apply_tone(img: &DynamicImage, tone: Tone, weights: [u32; 3], linear_light: bool) -> Result<DynamicImage, String> {
+    let converted = match tone {
+        Tone::Sepia => map_colors(img,
+            |pixel| SEPIA_MATRIX.map(|row| sepia_channel(row, pixel.map(u32::from), 255) as u8),
+            |pixel| SEPIA_MATRIX.map(|row| sepia_channel(row, pixel.map(u32::from), 65535) as u16)),
+        Tone::Tint(color) => {
+            let luma = Luma::new(weights, linear_light);
+            map_colors(img,
+                |pixel| color.map(|c| (luma.of8(pixel) as u32 * c as u32 / 255) as u8),
+                |pixel| color.map(|c| (luma.of16(pixel) as u32 * c as u32 / 255) as u16))
+        },
+    };
+    converted.ok_or_else(|| format!("Unsupported color type for {}: {:?}", tone.value(), img.color()))
+}
+
+// One output channel of the sepia matrix, clipped to the channel's range
+fn sepia_channel(row: [u32; 3], [r, g, b]: [u32; 3], max: u32) -> u32 {
+    ((r * row[0] + g * row[1] + b * row[2]) / 1000).min(max)
+}
+
+// Weighted luma of a pixel, in linear light when given the sRGB tables
+struct Luma {
+    weights: [u32; 3],
+    weight_sum: u32,
+    tables: Option<&'static SrgbTables>,
+}
+
+impl Luma {
+    fn new(weights: [u32; 3], linear_light: bool) -> Self {
+        let weight_sum = weights.iter().sum::<u32>().max(1);
+        Luma { weights, weight_sum, tables: if linear_light { Some(srgb_tables()) } else { None } }
+    }
+
+    fn of8(&self, pixel: [u8; 3]) -> u8 {
+        let [r_weight, g_weight, b_weight] = self.weights;
+        match self.tables {
+            Some(tables) => {
+                let [r, g, b] = pixel.map(|value| tables.to_linear[value as usize] as u32);
+                tables.to_srgb[((r * r_weight + g * g_weight + b * b_weight) / self.weight_sum) as usize]
+            },
+            None => ((pixel[0] as u32 * r_weight + pixel[1] as u32 * g_weight + pixel[2] as u32 * b_weight) / self.weight_sum) as u8,
+        }
+    }
+
+    fn of16(&self, pixel: [u16; 3]) -> u16 {
+        let [r_weight, g_weight, b_weight] = self.weights;
+        match self.tables {
+            Some(tables) => {
+                let [r, g, b] = pixel.map(|value| tables.to_linear16[value as usize] as u32);
+                tables.to_srgb16[((r * r_weight + g * g_weight + b * b_weight) / self.weight_sum) as usize]
+            },
+            None => ((pixel[0] as u32 * r_weight + pixel[1] as u32 * g_weight + pixel[2] as u32 * b_weight) / self.weight_sum) as u16,
+        }
+    }
+}
+
+// Replace the color of every pixel, as an RGBA8 image, or RGBA16 for 16-bit and float sources
+// so smooth gradients don't band; None for color types this doesn't know (newer image crate
+// variants), which are better refused than guessed at
+fn map_colors(
+    img: &DynamicImage,
+    map8: impl Fn([u8; 3]) -> [u8; 3] + Sync,
+    map16: impl Fn([u16; 3]) -> [u16; 3] + Sync,
+) -> Option<DynamicImage> {
+    let (width, height) = img.dimensions();
+    let image8 = |pixels| DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, pixels).expect("converted buffer matches the image size"));
+    let image16 = |pixels| DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, pixels).expect("converted buffer matches the image size"));
+    Some(match img {
+        // Handle RGBA images (with transparency)
+        DynamicImage::ImageRgba8(rgba_img) => image8(map_pixels(rgba_img.as_raw(), 4, u8::MAX, map8)),
+        // Handle RGB images (no transparency)
+        DynamicImage::ImageRgb8(rgb_img) => image8(map_pixels(rgb_img.as_raw(), 3, u8::MAX, map8)),
+        // 8-bit gray images convert to RGBA8 without losing anything
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => image8(map_pixels(img.to_rgba8().as_raw(), 4, u8::MAX, map8)),
+        DynamicImage::ImageRgba16(rgba_img) => image16(map_pixels(rgba_img.as_raw(), 4, u16::MAX, map16)),
+        DynamicImage::ImageRgb16(rgb_img) => image16(map_pixels(rgb_img.as_raw(), 3, u16::MAX, map16)),
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb32F(_)
+        | DynamicImage::ImageRgba32F(_) => image16(map_pixels(img.to_rgba16().as_raw(), 4, u16::MAX, map16)),
+        _ => return None,
+    })
+}
+
+// Pixels per rayon task, large enough that scheduling costs nothing next to the luma math
+const GRAYSCALE_CHUNK_PIXELS: usize = 16 * 1024;
+
+// sRGB transfer function lookup tables for gamma-correct grayscale
+struct SrgbTables {
+    to_linear: [u16; 256], // 8-bit sRGB to 16-bit linear light
+    to_srgb: Vec<u8>,      // 16-bit linear light to 8-bit sRGB, 65536 entries
+    to_linear16: Vec<u16>, // 16-bit sRGB to 16-bit linear light, 65536 entries
+    to_srgb16: Vec<u16>,   // 16-bit linear light to 16-bit sRGB, 65536 entries
+}
+
+// Built on first use and shared by every request afterwards
+fn srgb_tables() -> &'static SrgbTables {
+    static TABLES: OnceLock<SrgbTables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let decode = |v: f64| if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) };
+        let encode = |v: f64| if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 };
+        let mut to_linear = [0u16; 256];
+        for (value, linear) in to_linear.iter_mut().enumerate() {
+            *linear = (decode(value as f64 / 255.0) * 65535.0).round() as u16;
+        }
+        let to_srgb = (0..=u16::MAX)
+            .map(|linear| (encode(linear as f64 / 65535.0) * 255.0).round() as u8)
+            .collect();
+        let to_linear16 = (0..=u16::MAX)
+            .map(|value| (decode(value as f64 / 65535.0) * 65535.0).round() as u16)
+            .collect();
+        let to_srgb16 = (0..=u16::MAX)
+            .map(|linear| (encode(linear as f64 / 65535.0) * 65535.0).round() as u16)
+            .collect();
+        SrgbTables { to_linear, to_srgb, to_linear16, to_srgb16 }
+    })
+}
+
+// RGBA pixels from packed RGB (3 channels) or RGBA (4 channels) data, with map applied to the colors
+// Chunks are converted in parallel; alpha is kept, RGB input becomes opaque
+fn map_pixels<T: Copy + Default + Send + Sync>(input: &[T], channels: usize, opaque: T, map: impl Fn([T; 3]) -> [T; 3] + Sync) -> Vec<T> {
+    let mut output = vec![T::default(); input.len() / channels * 4];
+    output.par_chunks_mut(GRAYSCALE_CHUNK_PIXELS * 4)
+        .zip(input.par_chunks(GRAYSCALE_CHUNK_PIXELS * channels))
+        .for_each(|(output, input)| {
+            for (out, pixel) in output.chunks_exact_mut(4).zip(input.chunks_exact(channels)) {
+                let [r, g, b] = map([pixel[0], pixel[1], pixel[2]]);
+                let alpha = if channels == 4 { pixel[3] } else { opaque };
+                out.copy_from_slice(&[r, g, b, alpha]);
+            }
+        });
+    output
+}
+
+// 8x8 Bayer matrix, the order in which pixels of a flat area round up when dithering
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+// Whether an image has more than 8 bits per channel (16-bit or float)
+pub fn is_high_bit_depth(img: &DynamicImage) -> bool {
+    !matches!(img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_))
+}
+
+// The image as 8-bit RGB or RGBA, the only layouts the WebP encoder (and AVIF's for 8-bit sources) take
+// 16-bit and float images are reduced with ordered dithering: truncating them to 8 bits turns
+// smooth gradients into visible bands, dithering trades that for fine noise the eye averages out
+// None when the image already is 8-bit RGB or RGBA
+pub fn to_8bit_rgb(img: &DynamicImage) -> Option<DynamicImage> {
+    let (width, height) = img.dimensions();
+    match img {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => None,
+        DynamicImage::ImageLuma8(_) => Some(DynamicImage::ImageRgb8(img.to_rgb8())),
+        DynamicImage::ImageLumaA8(_) => Some(DynamicImage::ImageRgba8(img.to_rgba8())),
+        _ if img.color().has_alpha() => {
+            let pixels = dither_to_8bit(img.to_rgba16().as_raw(), width as usize * 4, 4);
+            Some(DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, pixels).expect("dithered buffer matches the image size")))
+        },
+        _ => {
+            let pixels = dither_to_8bit(img.to_rgb16().as_raw(), width as usize * 3, 3);
+            Some(DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, pixels).expect("dithered buffer matches the image size")))
+        },
+    }
+}
+
+// Ordered dithering of packed 16-bit pixels to 8 bits, one row per rayon task
+// Each pixel rounds up once its remainder passes its Bayer threshold, so an area between two
+// 8-bit levels becomes a pattern whose average is the original value
+fn dither_to_8bit(input: &[u16], row_len: usize, channels: usize) -> Vec<u8> {
+    let mut output = vec![0u8; input.len()];
+    if row_len == 0 {
+        return output;
+    }
+    output.par_chunks_mut(row_len)
+        .zip(input.par_chunks(row_len))
+        .enumerate()
+        .for_each(|(y, (output, input))| {
+            let thresholds = &BAYER_8X8[y % 8];
+            for (x, (out, pixel)) in output.chunks_exact_mut(channels).zip(input.chunks_exact(channels)).enumerate() {
+                // Between 0 and 65535, in the same units as value * 255
+                let threshold = (thresholds[x % 8] as u32 * 2 + 1) * 65535 / 128;
+                for (out, &value) in out.iter_mut().zip(pixel) {
+                    *out = ((value as u32 * 255 + threshold) / 65535) as u8;
+                }
+            }
+        });
+    output
+}
+
+// Gaussian blur radius of the unsharp mask, in pixels; sized for the detail resizing softens
+const SHARPEN_SIGMA: f32 = 1.0;
+
+// Unsharp mask: add back the difference between the image and a blurred copy of it
+// Strength 1-100 scales that difference by 0.02-2.0, so 50 is the classic amount of 1
+// Alpha is left alone, sharpening it would put halos around transparent edges
+pub fn sharpen_image(img: &DynamicImage, strength: u8) -> DynamicImage {
+    let amount = strength as f32 / 50.0;
+    match img {
+        DynamicImage::ImageRgba8(rgba_img) => DynamicImage::ImageRgba8(unsharp_mask(rgba_img, 4, amount, |value| value as u8)),
+        DynamicImage::ImageRgb8(rgb_img) => DynamicImage::ImageRgb8(unsharp_mask(rgb_img, 3, amount, |value| value as u8)),
+        DynamicImage::ImageRgba16(rgba_img) => DynamicImage::ImageRgba16(unsharp_mask(rgba_img, 4, amount, |value| value as u16)),
+        DynamicImage::ImageRgb16(rgb_img) => DynamicImage::ImageRgb16(unsharp_mask(rgb_img, 3, amount, |value| value as u16)),
+        // Everything else is rare after decoding and converts like grayscale does
+        _ if is_high_bit_depth(img) => DynamicImage::ImageRgba16(unsharp_mask(&img.to_rgba16(), 4, amount, |value| value as u16)),
+        _ => DynamicImage::ImageRgba8(unsharp_mask(&img.to_rgba8(), 4, amount, |value| value as u8)),
+    }
+}
+
+// Unsharp mask over packed 8 or 16-bit pixels, sharpening the first three channels
+// `from_f32` converts a result already rounded and clamped to the channel range
+fn unsharp_mask<P, S>(image: &ImageBuffer<P, Vec<S>>, channels: usize, amount: f32, from_f32: fn(f32) -> S) -> ImageBuffer<P, Vec<S>>
+where
+    P: Pixel<Subpixel = S> + 'static,
+    S: image::Primitive + Into<f32> + Send + Sync + 'static,
+{
+    let max = S::DEFAULT_MAX_VALUE.into();
+    let mut output = imageops::blur(image, SHARPEN_SIGMA);
+    output.par_chunks_mut(GRAYSCALE_CHUNK_PIXELS * channels)
+        .zip(image.as_raw().par_chunks(GRAYSCALE_CHUNK_PIXELS * channels))
+        .for_each(|(output, input)| {
+            for (out, pixel) in output.chunks_exact_mut(channels).zip(input.chunks_exact(channels)) {
+                for channel in 0..3 {
+                    let value: f32 = pixel[channel].into();
+                    out[channel] = from_f32((value + (value - out[channel].into()) * amount).round().clamp(0.0, max));
+                }
+                out[3..].copy_from_slice(&pixel[3..]);
+            }
+        });
+    output
+}
+
+// Add an ICCP chunk to an encoded WebP file
+// Simple (VP8/VP8L) files are upgraded to the extended VP8X layout, which can carry the profile
+pub fn embed_webp_icc_profile(webp: &[u8], icc_profile: &[u8], width: u32, height: u32) -> Vec<u8> {
+    if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return webp.to_vec();
+    }
+    let chunks = &webp[12..];
+
+    // VP8X header: 4-byte tag, size 10, flags byte, 3 reserved bytes, 24-bit canvas width-1/height-1
+    const ICC_FLAG: u8 = 0x20;
+    const ALPHA_FLAG: u8 = 0x10;
+    let (mut vp8x, image_chunks) = if chunks.starts_with(b"VP8X") && chunks.len() >= 18 {
+        (chunks[..18].to_vec(), &chunks[18..])
+    } else {
+        // The alpha flag has to describe the bitstream, not the source: libwebp drops an alpha
+        // channel that is fully opaque. Of the simple layouts only lossless can have alpha, in
+        // the VP8L header's alpha_is_used bit (bit 28 after the 0x2f signature); lossy files
+        // with transparency are always written as VP8X with an ALPH chunk
+        let has_alpha = chunks.starts_with(b"VP8L") && chunks.get(12).is_some_and(|&bits| bits & 0x10 != 0);
+        let mut vp8x = b"VP8X".to_vec();
+        vp8x.extend_from_slice(&10u32.to_le_bytes());
+        vp8x.extend_from_slice(&[if has_alpha { ALPHA_FLAG } else { 0 }, 0, 0, 0]);
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        (vp8x, chunks)
+    };
+    vp8x[8] |= ICC_FLAG;
+
+    // The ICCP chunk must come right after VP8X; chunks are padded to an even size
+    let mut output = Vec::with_capacity(webp.len() + icc_profile.len() + 32);
+    output.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    output.extend_from_slice(&vp8x);
+    output.extend_from_slice(b"ICCP");
+    output.extend_from_slice(&(icc_profile.len() as u32).to_le_bytes());
+    output.extend_from_slice(icc_profile);
+    if icc_profile.len() % 2 == 1 {
+        output.push(0);
+    }
+    output.extend_from_slice(image_chunks);
+
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    output
+}
+
+// Magic bytes of a bare JPEG XL codestream and of the ISO container around one
+const JXL_CODESTREAM: &[u8] = &[0xff, 0x0a];
+const JXL_CONTAINER: &[u8] = &[0, 0, 0, 0x0c, b'J', b'X', b'L', b' ', 0x0d, 0x0a, 0x87, 0x0a];
+
+// Remove EXIF, XMP, IPTC and comments from an image sent as the origin served it, so GPS
+// coordinates and camera serials don't leak through the proxy. Color profiles are kept, and so
+// is a JPEG or PNG EXIF orientation (in a minimal EXIF block) so photos aren't shown sideways
+// None for formats this can't rewrite (AVIF, TIFF, ...) and for malformed files
+pub fn strip_metadata(image: &[u8]) -> Option<Vec<u8>> {
+    if image.starts_with(JXL_CONTAINER) {
+        return strip_jxl_metadata(image);
+    }
+    // A bare JPEG XL codestream has nowhere to put metadata
+    if image.starts_with(JXL_CODESTREAM) {
+        return Some(image.to_vec());
+    }
+    match image::guess_format(image).ok()? {
+        ImageFormat::Jpeg => strip_jpeg_metadata(image),
+        ImageFormat::Png => strip_png_metadata(image),
+        ImageFormat::WebP => strip_webp_metadata(image),
+        ImageFormat::Gif => strip_gif_metadata(image),
+        _ => None,
+    }
+}
+
+// The TIFF structure of an EXIF block holding nothing but the orientation, or None when
+// the original block has no orientation that turns the image
+fn orientation_exif(exif: &[u8]) -> Option<Vec<u8>> {
+    let orientation = Orientation::from_exif_chunk(exif).filter(|&orientation| orientation != Orientation::NoTransforms)?;
+    // Big-endian header, one IFD at offset 8 with a single SHORT entry, no next IFD
+    let mut tiff = b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    tiff.extend_from_slice(&[0, orientation.to_exif(), 0, 0, 0, 0, 0, 0]);
+    Some(tiff)
+}
+
+// Segments are copied until the end of image marker; metadata APPn segments and comments are
+// dropped, and so is anything after the end marker (some cameras append thumbnails there)
+fn strip_jpeg_metadata(jpeg: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(jpeg.len());
+    output.extend_from_slice(&jpeg[..2]);
+    let mut pos = 2;
+    loop {
+        // Markers may be padded with extra 0xff fill bytes
+        while jpeg.get(pos..pos + 2) == Some(&[0xff, 0xff]) {
+            pos += 1;
+        }
+        if *jpeg.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *jpeg.get(pos + 1)?;
+        if marker == 0xd9 {
+            output.extend_from_slice(&[0xff, 0xd9]);
+            return Some(output);
+        }
+        let length = u16::from_be_bytes([*jpeg.get(pos + 2)?, *jpeg.get(pos + 3)?]) as usize;
+        let end = pos + 2 + length;
+        let payload = jpeg.get(pos + 4..end)?;
+        match marker {
+            0xe1 if payload.starts_with(b"Exif\0\0") => {
+                if let Some(tiff) = orientation_exif(&payload[6..]) {
+                    output.extend_from_slice(&[0xff, 0xe1]);
+                    output.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+                    output.extend_from_slice(b"Exif\0\0");
+                    output.extend_from_slice(&tiff);
+                }
+            },
+            // JFIF, ICC profiles and Adobe's color transform (needed to decode CMYK) stay
+            0xe0 | 0xee => output.extend_from_slice(&jpeg[pos..end]),
+            0xe2 if payload.starts_with(b"ICC_PROFILE\0") => output.extend_from_slice(&jpeg[pos..end]),
+            0xe1..=0xef | 0xfe => {},
+            _ => output.extend_from_slice(&jpeg[pos..end]),
+        }
+        pos = end;
+        // Entropy-coded data follows a scan header, up to the next marker that isn't
+        // a stuffed 0xff00 or a restart marker
+        if marker == 0xda {
+            let scan_length = jpeg[pos..].windows(2)
+                .position(|bytes| bytes[0] == 0xff && bytes[1] != 0 && !(0xd0..=0xd7).contains(&bytes[1]))?;
+            output.extend_from_slice(&jpeg[pos..pos + scan_length]);
+            pos += scan_length;
+        }
+    }
+}
+
+// Text chunks and the modification time are dropped, eXIf is cut down to the orientation
+fn strip_png_metadata(png: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(png.len());
+    output.extend_from_slice(png.get(..8)?);
+    let mut pos = 8;
+    loop {
+        let length = u32::from_be_bytes(png.get(pos..pos + 4)?.try_into().unwrap()) as usize;
+        let end = pos.checked_add(length)?.checked_add(12)?;
+        let chunk = png.get(pos..end)?;
+        match &chunk[4..8] {
+            b"eXIf" => {
+                if let Some(tiff) = orientation_exif(&chunk[8..8 + length]) {
+                    let start = output.len();
+                    output.extend_from_slice(&(tiff.len() as u32).to_be_bytes());
+                    output.extend_from_slice(b"eXIf");
+                    output.extend_from_slice(&tiff);
+                    let crc = crc32fast::hash(&output[start + 4..]);
+                    output.extend_from_slice(&crc.to_be_bytes());
+                }
+            },
+            b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => {},
+            _ => output.extend_from_slice(chunk),
+        }
+        if &chunk[4..8] == b"IEND" {
+            return Some(output);
+        }
+        pos = end;
+    }
+}
+
+// EXIF and XMP chunks are dropped along with their VP8X flags; browsers ignore
+// the EXIF orientation of WebP images, so it isn't kept
+fn strip_webp_metadata(webp: &[u8]) -> Option<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+    let mut output = Vec::with_capacity(webp.len());
+    output.extend_from_slice(webp.get(..12)?);
+    let mut pos = 12;
+    while pos < webp.len() {
+        let size = u32::from_le_bytes(webp.get(pos + 4..pos + 8)?.try_into().unwrap()) as usize;
+        // Chunks are padded to an even size, though encoders sometimes leave out the last pad byte
+        let end = (pos + 8).checked_add(size + size % 2)?.min(webp.len());
+        let chunk = webp.get(pos..end)?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {},
+            b"VP8X" if chunk.len() > 8 => {
+                let flags = output.len() + 8;
+                output.extend_from_slice(chunk);
+                output[flags] &= !(EXIF_FLAG | XMP_FLAG);
+            },
+            _ => output.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(output)
+}
+
+// Comments and application extensions other than the animation loop count are dropped
+fn strip_gif_metadata(gif: &[u8]) -> Option<Vec<u8>> {
+    // Header and logical screen descriptor, then the global color table if there is one
+    let mut pos = 13 + gif_color_table_size(*gif.get(10)?);
+    let mut output = Vec::with_capacity(gif.len());
+    output.extend_from_slice(gif.get(..pos)?);
+    loop {
+        match *gif.get(pos)? {
+            // Trailer
+            0x3b => {
+                output.push(0x3b);
+                return Some(output);
+            },
+            // Extension: label, then data sub-blocks
+            0x21 => {
+                let end = gif_sub_blocks_end(gif, pos + 2)?;
+                let keep = match *gif.get(pos + 1)? {
+                    0xfe => false,
+                    0xff => matches!(gif.get(pos + 3..pos + 14), Some(b"NETSCAPE2.0" | b"ANIMEXTS1.0")),
+                    _ => true,
+                };
+                if keep {
+                    output.extend_from_slice(&gif[pos..end]);
+                }
+                pos = end;
+            },
+            // Image: descriptor, local color table, LZW code size, then data sub-blocks
+            0x2c => {
+                let data = pos + 10 + gif_color_table_size(*gif.get(pos + 9)?) + 1;
+                let end = gif_sub_blocks_end(gif, data)?;
+                output.extend_from_slice(&gif[pos..end]);
+                pos = end;
+            },
+            _ => return None,
+        }
+    }
+}
+
+fn gif_color_table_size(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        return 0;
+    }
+    3 << ((flags & 0x07) + 1)
+}
+
+// Position after a chain of sub-blocks, which ends with an empty one
+fn gif_sub_blocks_end(gif: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let size = *gif.get(pos)? as usize;
+        pos += 1 + size;
+        if size == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+// Exif, XMP and JUMBF boxes are dropped, and so are Brotli-compressed boxes, which only ever
+// hold metadata
+fn strip_jxl_metadata(jxl: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(jxl.len());
+    let mut pos = 0;
+    while pos < jxl.len() {
+        let size = match u32::from_be_bytes(jxl.get(pos..pos + 4)?.try_into().unwrap()) {
+            // The last box may run to the end of the file
+            0 => jxl.len() - pos,
+            // 64-bit size after the box type
+            1 => usize::try_from(u64::from_be_bytes(jxl.get(pos + 8..pos + 16)?.try_into().unwrap())).ok()?,
+            size => size as usize,
+        };
+        let end = pos.checked_add(size)?;
+        let jxl_box = jxl.get(pos..end).filter(|jxl_box| jxl_box.len() >= 8)?;
+        if !matches!(&jxl_box[4..8], b"Exif" | b"xml " | b"jumb" | b"brob") {
+            output.extend_from_slice(jxl_box);
+        }
+        pos = end;
+    }
+    Some(output)
+}
+
+// Encoder effort, picked by whoever runs the server rather than per request
+#[derive(Clone, Copy, Debug)]
+pub struct EncoderSettings {
+    pub jxl_speed: EncoderSpeed,
+    pub jxl_curve_exponent: f32,      // Shape of the quality to distance mapping, see DEFAULT_JXL_CURVE_EXPONENT
+    pub jxl_lossless_threshold: u8,   // Qualities from this one up are lossless, above 100 never
+    pub avif_speed: u8,               // 1 (slowest, smallest files) to 10 (fastest)
+    pub avif_threads: Option<usize>,  // None uses one thread per CPU
+    pub webp_method: Option<u8>,      // 0 (fastest) to 6 (best compression), None keeps libwebp's 4
+    pub webp_sharp_yuv: bool,         // Slower, sharper RGB to YUV conversion for lossy WebP
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            jxl_speed: EncoderSpeed::Tortoise,
+            jxl_curve_exponent: DEFAULT_JXL_CURVE_EXPONENT,
+            jxl_lossless_threshold: DEFAULT_JXL_LOSSLESS_THRESHOLD,
+            avif_speed: DEFAULT_AVIF_SPEED,
+            avif_threads: None,
+            webp_method: None,
+            webp_sharp_yuv: false,
+        }
+    }
+}
+
+// libwebp settings for a WebP encode, still or animated
+fn webp_config(quality: u8, compression: Compression, settings: &EncoderSettings) -> Result<webp::WebPConfig, String> {
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| "WebP encoding error: invalid libwebp configuration".to_string())?;
+    config.quality = quality as f32;
+    if let Some(method) = settings.webp_method {
+        config.method = method as i32;
+    }
+    config.use_sharp_yuv = i32::from(settings.webp_sharp_yuv);
+    match compression {
+        Compression::Lossy => {},
+        Compression::NearLossless => {
+            // Near-lossless preprocessing level, 100 would be exact and 0 the strongest
+            config.lossless = 1;
+            config.near_lossless = quality as i32;
+            config.alpha_compression = 0;
+        },
+        Compression::Lossless => {
+            // exact keeps the color of fully transparent pixels, so RGBA round-trips untouched
+            config.lossless = 1;
+            config.exact = 1;
+            config.alpha_compression = 0;
+        },
+    }
+    Ok(config)
+}
+
+// Encode the frames of an animation, all the same size, as an animated WebP
+// Each frame comes with its delay in milliseconds; a loop_count of 0 repeats forever
+pub fn encode_animated_webp(
+    frames: &[(DynamicImage, u32)],
+    quality: u8,
+    compression: Compression,
+    settings: &EncoderSettings,
+    loop_count: u32,
+) -> Result<Bytes, String> {
+    let Some((first, _)) = frames.first() else {
+        return Err("WebP animation error: no frames".to_string());
+    };
+    let config = webp_config(quality, compression, settings)?;
+    let pixels: Vec<Cow<RgbaImage>> = frames.iter()
+        .map(|(frame, _)| match frame {
+            DynamicImage::ImageRgba8(rgba) => Cow::Borrowed(rgba),
+            other => Cow::Owned(other.to_rgba8()),
+        })
+        .collect();
+
+    // Frames are placed by their start time, and the final timestamp is when the last one ends
+    let mut encoder = AnimationEncoder::new(first.width(), first.height(), loop_count)?;
+    let mut timestamp = 0;
+    for (rgba, (_, delay)) in pixels.iter().zip(frames) {
+        encoder.add_frame(rgba, timestamp, &config)?;
+        timestamp += *delay as i32;
+    }
+    let webp_data = encoder.finish(timestamp)?;
+    debug!(frames = frames.len(), "Encoded animation as WebP");
+    Ok(Bytes::from_owner(webp_data))
+}
+
+// libwebp's WebPAnimEncoder, used directly: the webp crate's AnimEncoder always ends the
+// animation at timestamp 0, which libwebp rejects and then guesses the last frame's delay
+struct AnimationEncoder(*mut libwebp_sys::WebPAnimEncoder);
+
+impl AnimationEncoder {
+    fn new(width: u32, height: u32, loop_count: u32) -> Result<Self, String> {
+        unsafe {
+            let mux_abi_version = libwebp_sys::WebPGetMuxABIVersion();
+            let mut options = MaybeUninit::<libwebp_sys::WebPAnimEncoderOptions>::uninit();
+            if libwebp_sys::WebPAnimEncoderOptionsInitInternal(options.as_mut_ptr(), mux_abi_version) == 0 {
+                return Err("WebP animation error: libwebp version mismatch".to_string());
+            }
+            let mut options = options.assume_init();
+            options.anim_params.loop_count = loop_count as i32;
+            let encoder = libwebp_sys::WebPAnimEncoderNewInternal(width as i32, height as i32, &options, mux_abi_version);
+            if encoder.is_null() {
+                return Err("WebP animation error: could not create encoder".to_string());
+            }
+            Ok(AnimationEncoder(encoder))
+        }
+    }
+
+    // libwebp copies the frame, so the picture is released right after
+    fn add_frame(&mut self, rgba: &RgbaImage, timestamp: i32, config: &libwebp_sys::WebPConfig) -> Result<(), String> {
+        let mut picture = libwebp_sys::WebPPicture::new()
+            .map_err(|_| "WebP animation error: libwebp version mismatch".to_string())?;
+        picture.use_argb = 1;
+        picture.width = rgba.width() as i32;
+        picture.height = rgba.height() as i32;
+        unsafe {
+            let added = libwebp_sys::WebPPictureImportRGBA(&mut picture, rgba.as_ptr(), rgba.width() as i32 * 4) != 0
+                && libwebp_sys::WebPAnimEncoderAdd(self.0, &mut picture, timestamp, config) != 0;
+            libwebp_sys::WebPPictureFree(&mut picture);
+            if !added {
+                return Err(self.error());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self, end_timestamp: i32) -> Result<WebPData, String> {
+        unsafe {
+            let mut data = libwebp_sys::WebPData::default();
+            if libwebp_sys::WebPAnimEncoderAdd(self.0, std::ptr::null_mut(), end_timestamp, std::ptr::null()) == 0
+                || libwebp_sys::WebPAnimEncoderAssemble(self.0, &mut data) == 0
+            {
+                return Err(self.error());
+            }
+            Ok(WebPData(data))
+        }
+    }
+
+    fn error(&self) -> String {
+        let message = unsafe { libwebp_sys::WebPAnimEncoderGetError(self.0) };
+        if message.is_null() {
+            return "WebP animation error: unknown".to_string();
+        }
+        format!("WebP animation error: {}", unsafe { CStr::from_ptr(message) }.to_string_lossy())
+    }
+}
+
+impl Drop for AnimationEncoder {
+    fn drop(&mut self) {
+        unsafe { libwebp_sys::WebPAnimEncoderDelete(self.0) }
+    }
+}
+
+// An assembled animation, owned by libwebp until dropped
+// Just a pointer and length to a buffer released with WebPFree, which is safe from any thread;
+// it is only missing Send because it holds a raw pointer
+struct WebPData(libwebp_sys::WebPData);
+
+unsafe impl Send for WebPData {}
+
+impl AsRef<[u8]> for WebPData {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.0.bytes, self.0.size) }
+    }
+}
+
+impl Drop for WebPData {
+    fn drop(&mut self) {
+        unsafe { libwebp_sys::WebPDataClear(&mut self.0) }
+    }
+}
+
+// Sample types JXL output is encoded from: 8-bit, or 16-bit to keep the precision of deeper sources
+// jpegxl-rs keeps its own PixelType trait private, so it can't be the bound of encode_jxl
+trait JxlSample: Primitive {
+    // Packed RGB or RGBA samples of the image
+    fn rgb(img: &DynamicImage) -> Vec<Self>;
+    fn rgba(img: &DynamicImage) -> Vec<Self>;
+    fn encode(encoder: &mut JxlEncoder, pixels: &[Self], channels: u32, width: u32, height: u32) -> Result<Vec<u8>, EncodeError>;
+}
+
+impl JxlSample for u8 {
+    fn rgb(img: &DynamicImage) -> Vec<u8> {
+        img.to_rgb8().into_raw()
+    }
+
+    fn rgba(img: &DynamicImage) -> Vec<u8> {
+        img.to_rgba8().into_raw()
+    }
+
+    fn encode(encoder: &mut JxlEncoder, pixels: &[u8], channels: u32, width: u32, height: u32) -> Result<Vec<u8>, EncodeError> {
+        let encoded: EncoderResult<u8> = encoder.encode_frame(&EncoderFrame::new(pixels).num_channels(channels), width, height)?;
+        Ok(encoded.data)
+    }
+}
+
+impl JxlSample for u16 {
+    fn rgb(img: &DynamicImage) -> Vec<u16> {
+        img.to_rgb16().into_raw()
+    }
+
+    fn rgba(img: &DynamicImage) -> Vec<u16> {
+        img.to_rgba16().into_raw()
+    }
+
+    fn encode(encoder: &mut JxlEncoder, pixels: &[u16], channels: u32, width: u32, height: u32) -> Result<Vec<u8>, EncodeError> {
+        let encoded: EncoderResult<u16> = encoder.encode_frame(&EncoderFrame::new(pixels).num_channels(channels), width, height)?;
+        Ok(encoded.data)
+    }
+}
+
+// Encode an image as JXL with samples of type S
+// Keep the alpha channel only when there is transparency to keep (grayscale output is always
+// RGBA); opaque images use the cheaper RGB path. The encoder is built once that is known
+fn encode_jxl<'prl, 'mm, S: JxlSample>(
+    img: &DynamicImage,
+    build_encoder: impl FnOnce(bool) -> Result<JxlEncoder<'prl, 'mm>, String>,
+) -> Result<Vec<u8>, String> {
+    let rgba = img.color().has_alpha().then(|| S::rgba(img))
+        .filter(|rgba| rgba.chunks_exact(4).any(|pixel| pixel[3] < S::DEFAULT_MAX_VALUE));
+    let mut encoder = build_encoder(rgba.is_some())?;
+    match &rgba {
+        Some(rgba) => S::encode(&mut encoder, rgba, 4, img.width(), img.height()),
+        None => S::encode(&mut encoder, &S::rgb(img), 3, img.width(), img.height()),
+    }.map_err(|e| format!("JXL encoding error: {}", e))
+}
+
+// BT.601 luma weights ravif uses for its YCbCr planes
+const AVIF_LUMA: [f32; 3] = [0.299, 0.587, 0.114];
+
+// Encode a 16-bit or float image as 10-bit AVIF, with ravif directly since image's encoder
+// only takes 8 bits. Same settings image's encoder uses: full range BT.601 YCbCr, 4:4:4,
+// and the color quality for alpha too, which is only stored when there is transparency
+fn encode_avif_10bit(img: &DynamicImage, quality: u8, settings: &EncoderSettings) -> Result<Vec<u8>, String> {
+    let rgba = img.to_rgba16();
+    let has_alpha = img.color().has_alpha() && rgba.pixels().any(|pixel| pixel[3] < u16::MAX);
+    let encoder = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_alpha_quality(quality as f32)
+        .with_speed(settings.avif_speed)
+        .with_num_threads(settings.avif_threads)
+        .with_bit_depth(ravif::BitDepth::Ten);
+
+    let [kr, kg, kb] = AVIF_LUMA;
+    let scale = 1023.0 / 65535.0;
+    let planes = rgba.pixels().map(|pixel| {
+        let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|value| value as f32 * scale);
+        let y = kr * r + kg * g + kb * b;
+        let cb = (b - y) * 0.5 / (1.0 - kb) + 512.0;
+        let cr = (r - y) * 0.5 / (1.0 - kr) + 512.0;
+        [y, cb, cr].map(|value| value.round().clamp(0.0, 1023.0) as u16)
+    });
+    let alpha = has_alpha.then(|| rgba.pixels().map(|pixel| pixel[3] >> 6));
+    encoder.encode_raw_planes_10_bit(img.width() as usize, img.height() as usize, planes, alpha,
+        ravif::PixelRange::Full, ravif::MatrixCoefficients::BT601)
+        .map(|encoded| encoded.avif_file)
+        .map_err(|e| format!("AVIF encoding error: {}", e))
+}
+
+// Encode an image into the given format, returning the encoded bytes and their content type
+// Lossy at the given quality, with the server's default encoder settings
+pub fn encode_image(img: &DynamicImage, format: OutputFormat, quality: u8) -> Result<(Bytes, &'static str), String> {
+    encode_image_with(img, format, quality, Compression::Lossy, &EncoderSettings::default(), None)
+}
+
+// encode_image with every knob the server has
+// An ICC profile, when given, is embedded in WebP output
+pub fn encode_image_with(
+    img: &DynamicImage,
+    format: OutputFormat,
+    quality: u8,
+    compression: Compression,
+    settings: &EncoderSettings,
+    icc_profile: Option<&[u8]>,
+) -> Result<(Bytes, &'static str), String> {
+    match format {
+        OutputFormat::Jxl => {
+            // JXL quality is inverse of standard quality:
+            // - Lower numbers mean better quality (0 is lossless)
+            // - Higher numbers mean more compression
+            let lossless = compression == Compression::Lossless || quality >= settings.jxl_lossless_threshold;
+            let jxl_quality = if lossless {
+                0.0  // Use lossless mode for very high quality requests
+            } else {
+                let normalized = quality as f32 / 100.0;
+                // Use exponential curve to make quality changes more gradual
+                // This gives better quality preservation at lower input values
+                8.0 * (1.0 - normalized.powf(settings.jxl_curve_exponent))
+            };
+
+            // Create JXL encoder with the configured speed
+            let build_encoder = |has_alpha: bool| {
+                let mut encoder = encoder_builder()
+                    .speed(settings.jxl_speed)
+                    .has_alpha(has_alpha)
+                    .build()
+                    .map_err(|e| format!("JXL encoder creation error: {}", e))?;
+                encoder.quality = jxl_quality;
+                encoder.lossless = lossless;
+                Ok::<_, String>(encoder)
+            };
+
+            // 16-bit and float sources are encoded with 16 bits per channel, JXL keeps that precision
+            let data = if is_high_bit_depth(img) {
+                encode_jxl::<u16>(img, build_encoder)?
+            } else {
+                encode_jxl::<u8>(img, build_encoder)?
+            };
+
+            debug!("Encoded image as JXL");
+            Ok((Bytes::from(data), format.content_type()))
+        },
+        OutputFormat::Avif => {
+            // AVIF quality is 1-100 like WebP, and there's no lossless mode so graphics get 100
+            let avif_quality = if compression == Compression::Lossless { 100 } else { quality.max(1) };
+            // 16-bit and float sources are encoded as 10-bit AVIF to keep their gradients smooth;
+            // image's AVIF encoder only takes 8-bit input and writes 8-bit files
+            let avif_data = if is_high_bit_depth(img) {
+                encode_avif_10bit(img, avif_quality, settings)?
+            } else {
+                let mut avif_data = Vec::new();
+                let encoder = AvifEncoder::new_with_speed_quality(&mut avif_data, settings.avif_speed, avif_quality)
+                    .with_num_threads(settings.avif_threads);
+                let rgb = to_8bit_rgb(img);
+                rgb.as_ref().unwrap_or(img).write_with_encoder(encoder)
+                    .map_err(|e| format!("AVIF encoding error: {}", e))?;
+                avif_data
+            };
+
+            debug!("Encoded image as AVIF");
+            Ok((Bytes::from(avif_data), format.content_type()))
+        },
+        OutputFormat::WebP => {
+            // WebP encoding - quality is straightforward 0-100
+            // In the lossless modes it is the compression effort instead, higher is smaller and slower
+            // WebP is 8-bit RGB(A) only: gray images are expanded and deeper ones dithered down
+            let rgb = to_8bit_rgb(img);
+            let img = rgb.as_ref().unwrap_or(img);
+            let webp_encoder = webp::Encoder::from_image(img)
+                .map_err(|e| format!("WebP encoding error: {}", e))?;
+            let config = webp_config(quality, compression, settings)?;
+
+            // encode_advanced reports failures (e.g. dimensions over 16383px) instead of panicking
+            let webp_image = webp_encoder.encode_advanced(&config)
+                .map_err(|e| format!("WebP encoding error: {:?}", e))?;
+            let webp_data = match icc_profile {
+                Some(icc_profile) => Bytes::from(embed_webp_icc_profile(&webp_image, icc_profile, img.width(), img.height())),
+                None => Bytes::copy_from_slice(&webp_image),
+            };
+            debug!("Encoded image as WebP");
+            Ok((webp_data, format.content_type()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{AnimationDecoder, Rgb, RgbImage, Rgba};
+    use std::io::Cursor;
+
+    fn query(query: &str) -> ImageParams {
+        parse_query(query, 40, true).unwrap()
+    }
+
+    #[test]
+    fn parse_query_reads_control_params() {
+        let params = query("url=https%3A%2F%2Fexample.com%2Fa.jpg&l=75&bw=0&w=300&fit=cover&format=avif");
+        assert_eq!(params.url, "https://example.com/a.jpg");
+        assert_eq!(params.quality, 75);
+        assert!(params.quality_set);
+        assert!(!params.grayscale);
+        assert_eq!(params.width, Some(300));
+        assert_eq!(params.height, None);
+        assert_eq!(params.fit, Fit::Cover);
+        assert_eq!(params.format, Some(OutputFormat::Avif));
+    }
+
+    #[test]
+    fn parse_query_applies_server_defaults() {
+        let params = query("url=https://example.com/a.jpg");
+        assert_eq!(params.quality, 40);
+        assert!(!params.quality_set);
+        assert!(params.grayscale);
+        assert_eq!(params.luma_weights, DEFAULT_LUMA_WEIGHTS);
+    }
+
+    #[test]
+    fn parse_query_keeps_signed_urls_whole() {
+        let unencoded = query("url=https://cdn.example.com/img.jpg?sig=abc&exp=123&l=50");
+        assert_eq!(unencoded.url, "https://cdn.example.com/img.jpg?sig=abc&exp=123");
+        assert_eq!(unencoded.quality, 50);
+
+        let encoded = query("url=https%3A%2F%2Fcdn.example.com%2Fimg.jpg%3Fsig%3Dabc%26exp%3D123&l=50");
+        assert_eq!(encoded.url, "https://cdn.example.com/img.jpg?sig=abc&exp=123");
+        assert_eq!(encoded.quality, 50);
+    }
+
+    #[test]
+    fn parse_query_ignores_unknown_params_after_an_encoded_url() {
+        let params = query("url=https%3A%2F%2Fx%2Fa.jpg&jpeg=1&bw=0");
+        assert_eq!(params.url, "https://x/a.jpg");
+        assert!(!params.grayscale);
+
+        let params = query("url=https%3A%2F%2Fcdn%2Fimg.jpg%3Fsig%3Dabc&jpeg=1&l=30");
+        assert_eq!(params.url, "https://cdn/img.jpg?sig=abc");
+        assert_eq!(params.quality, 30);
+    }
+
+    #[test]
+    fn parse_query_clamps_quality() {
+        assert_eq!(query("url=x&l=0").quality, 1);
+        assert_eq!(query("url=x&l=100").quality, 100);
+        assert_eq!(query("url=x&l=500").quality, 100);
+        assert!(parse_query("url=x&l=-5", 80, false).is_err());
+        assert!(parse_query("url=x&l=high", 80, false).is_err());
+    }
+
+    #[test]
+    fn parse_query_scales_dimensions_to_device_pixels() {
+        let params = query("url=x&w=100&h=50&variant=@2x");
+        assert_eq!((params.width, params.height), (Some(200), Some(100)));
+        assert_eq!(query("url=x&w=100&dpr=1.5").width, Some(150));
+        assert_eq!(query("url=x&w=0").width, None);
+        assert_eq!(query("url=x&w=99999&dpr=4").width, Some(MAX_REQUESTED_DIMENSION));
+    }
+
+    #[test]
+    fn parse_query_prefers_lumacoef_over_coeff() {
+        assert_eq!(query("url=x&coeff=709").luma_weights, REC709_LUMA_WEIGHTS);
+        assert_eq!(query("url=x&lumacoef=0.5,0.25,0.25&coeff=709").luma_weights, [500, 250, 250]);
+        assert_eq!(query("url=x&coeff=709&lumacoef=0.5,0.25,0.25").luma_weights, [500, 250, 250]);
+    }
+
+    #[test]
+    fn parse_luma_coefficients_validates_weights() {
+        assert_eq!(parse_luma_coefficients("0.2126,0.7152,0.0722"), Ok(REC709_LUMA_WEIGHTS));
+        assert_eq!(parse_luma_coefficients(" 1, 0 ,0"), Ok([1000, 0, 0]));
+        assert!(parse_luma_coefficients("0.5,0.5").is_err());
+        assert!(parse_luma_coefficients("0.5,0.5,0,0").is_err());
+        assert!(parse_luma_coefficients("1.5,-0.5,0").is_err());
+        assert!(parse_luma_coefficients("0.3,0.3,0.3").is_err());
+        assert!(parse_luma_coefficients("NaN,0.5,0.5").is_err());
+        assert!(parse_luma_coefficients("red,green,blue").is_err());
+    }
+
+    #[test]
+    fn grayscale_uses_the_chosen_luma_coefficients() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([200, 100, 50]) } else { Rgb([0, 255, 0]) }));
+        let luma = |weights| {
+            let gray = convert_to_grayscale_optimized(&img, weights, false, false).unwrap().to_rgb8();
+            (gray.get_pixel(0, 0).0, gray.get_pixel(1, 0).0)
+        };
+        assert_eq!(luma(DEFAULT_LUMA_WEIGHTS), ([124; 3], [149; 3]));
+        assert_eq!(luma(REC709_LUMA_WEIGHTS), ([117; 3], [182; 3]));
+    }
+
+    #[test]
+    fn parse_crop_needs_four_numbers_and_a_size() {
+        assert_eq!(parse_crop("10,20,300,400"), Ok([10, 20, 300, 400]));
+        assert_eq!(parse_crop("0, 0, 1, 1"), Ok([0, 0, 1, 1]));
+        assert!(parse_crop("10,20,0,400").is_err());
+        assert!(parse_crop("10,20,300").is_err());
+        assert!(parse_crop("10,20,300,400,5").is_err());
+        assert!(parse_crop("-1,0,10,10").is_err());
+        assert!(parse_crop("a,b,c,d").is_err());
+    }
+
+    #[test]
+    fn parse_tone_needs_sepia_or_six_hex_digits() {
+        assert_eq!(parse_tone("sepia"), Ok(Tone::Sepia));
+        assert_eq!(parse_tone("Tint:FF8000"), Ok(Tone::Tint([255, 128, 0])));
+        assert!(parse_tone("tint:ff800").is_err());
+        assert!(parse_tone("tint:ff80000").is_err());
+        assert!(parse_tone("tint:gg8000").is_err());
+        assert!(parse_tone("tint:+f8000").is_err());
+        assert!(parse_tone("tint:#ff8000").is_err());
+        assert!(parse_tone("ff8000").is_err());
+        assert!(parse_tone("tint:").is_err());
+    }
+
+    #[test]
+    fn tones_map_known_colors() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([200, 100, 50, 90])));
+        let sepia = apply_tone(&img, Tone::Sepia, DEFAULT_LUMA_WEIGHTS, false).unwrap().to_rgba8();
+        assert_eq!(sepia.get_pixel(0, 0), &Rgba([164, 146, 114, 90]));
+        let tint = apply_tone(&img, Tone::Tint([255, 128, 0]), DEFAULT_LUMA_WEIGHTS, false).unwrap().to_rgba8();
+        assert_eq!(tint.get_pixel(0, 0), &Rgba([124, 62, 0, 90]));
+    }
+
+    #[test]
+    fn brightness_contrast_of_zero_changes_nothing() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, 77])));
+        let adjusted = adjust_brightness_contrast(&img, 0, 0).unwrap();
+        assert_eq!(adjusted.to_rgb8(), img.to_rgb8());
+    }
+
+    #[test]
+    fn brightness_contrast_extremes_keep_alpha() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([10, 128, 240, 90])));
+        let white = adjust_brightness_contrast(&img, 100, 0).unwrap().to_rgba8();
+        assert_eq!(white.get_pixel(0, 0), &Rgba([255, 255, 255, 90]));
+        let flat = adjust_brightness_contrast(&img, 0, -100).unwrap().to_rgba8();
+        assert_eq!(flat.get_pixel(0, 0), &Rgba([128, 128, 128, 90]));
+        let darker = adjust_brightness_contrast(&img, -20, 0).unwrap().to_rgba8();
+        assert_eq!(darker.get_pixel(0, 0), &Rgba([0, 77, 189, 90]));
+    }
+
+    #[test]
+    fn brightness_is_scaled_on_16_bit_images() {
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(2, 2, Rgba::<u16>([0, 32768, 65535, 65535])));
+        let adjusted = adjust_brightness_contrast(&img, 20, 0).unwrap();
+        assert_eq!(adjusted.to_rgba16().get_pixel(0, 0), &Rgba::<u16>([13107, 45875, 65535, 65535]));
+    }
+
+    // A small JPEG with a comment, an XMP block and an ICC profile after the start of image marker
+    fn jpeg_with_metadata() -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([200, 30, 30])))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg).unwrap();
+        let mut segments = Vec::new();
+        for (marker, payload) in [
+            (0xfe, b"secret comment".to_vec()),
+            (0xe1, b"http://ns.adobe.com/xap/1.0/\0<gps/>".to_vec()),
+            (0xe2, b"ICC_PROFILE\0\x01\x01profile".to_vec()),
+        ] {
+            segments.extend_from_slice(&[0xff, marker]);
+            segments.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+            segments.extend_from_slice(&payload);
+        }
+        jpeg.splice(2..2, segments);
+        jpeg
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn strip_metadata_drops_jpeg_comments_and_xmp() {
+        let jpeg = jpeg_with_metadata();
+        let stripped = strip_metadata(&jpeg).unwrap();
+        assert!(!contains(&stripped, b"secret comment"));
+        assert!(!contains(&stripped, b"<gps/>"));
+        assert!(contains(&stripped, b"ICC_PROFILE\0"));
+        let original = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(image::load_from_memory(&stripped).unwrap().to_rgb8(), original.to_rgb8());
+    }
+
+    #[test]
+    fn strip_metadata_drops_png_text_chunks() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([1, 2, 3, 4])))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let text = b"tEXtComment\0secret comment";
+        let mut chunk = ((text.len() - 4) as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(text);
+        chunk.extend_from_slice(&crc32fast::hash(text).to_be_bytes());
+        // After the signature and the IHDR chunk
+        png.splice(33..33, chunk);
+        assert!(image::load_from_memory(&png).is_ok());
+
+        let stripped = strip_metadata(&png).unwrap();
+        assert!(!contains(&stripped, b"secret comment"));
+        assert_eq!(stripped.len(), png.len() - text.len() - 8);
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn strip_metadata_refuses_unknown_and_malformed_files() {
+        let avif = b"\0\0\0\x1cftypavif\0\0\0\0";
+        assert_eq!(strip_metadata(avif), None);
+        assert_eq!(strip_metadata(b"<html></html>"), None);
+        let jpeg = jpeg_with_metadata();
+        assert_eq!(strip_metadata(&jpeg[..40]), None);
+    }
+
+    // Chunk ids at the top level of a RIFF WebP file, after the RIFF header and the ANIM/VP8X ones
+    fn webp_chunks(webp: &[u8]) -> Vec<[u8; 4]> {
+        let mut chunks = Vec::new();
+        let mut pos = 12;
+        while pos + 8 <= webp.len() {
+            let size = u32::from_le_bytes(webp[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            chunks.push(webp[pos..pos + 4].try_into().unwrap());
+            pos += 8 + size + size % 2;
+        }
+        chunks
+    }
+
+    #[test]
+    fn animated_webp_keeps_every_frame() {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        let frames: Vec<(DynamicImage, u32)> = colors.iter()
+            .map(|&color| (DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 8, Rgb(color))), 100))
+            .collect();
+        let webp = encode_animated_webp(&frames, 80, Compression::Lossless, &EncoderSettings::default(), 0).unwrap();
+
+        let chunks = webp_chunks(&webp);
+        assert_eq!(chunks.iter().filter(|&id| id == b"ANMF").count(), 3);
+        assert!(chunks.contains(b"ANIM"));
+
+        let decoded: Vec<_> = image::codecs::webp::WebPDecoder::new(Cursor::new(&webp[..])).unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(decoded.len(), 3);
+        for (frame, color) in decoded.iter().zip(colors) {
+            assert_eq!(frame.buffer().dimensions(), (16, 8));
+            // Later frames are blended over earlier ones, which image's decoder rounds down by one
+            let pixel = frame.buffer().get_pixel(8, 4).0;
+            assert!(pixel.iter().zip([color[0], color[1], color[2], 255]).all(|(&a, b)| a.abs_diff(b) <= 1), "{:?}", pixel);
+            assert_eq!(frame.delay().numer_denom_ms(), (100, 1));
+        }
+    }
+
+    // A 16-bit ramp across four 8-bit levels, repeated on every row
+    fn gradient16(width: u32, height: u32) -> Vec<u16> {
+        (0..height).flat_map(|_| (0..width).map(move |x| (20000 + x * 4 * 257 / width) as u16)).collect()
+    }
+
+    // Longest run of equal values in a row
+    fn longest_run(row: &[u8]) -> usize {
+        row.chunk_by(|a, b| a == b).map(<[u8]>::len).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn dithering_16bit_gradients_leaves_no_bands() {
+        let (width, height) = (512, 16);
+        let input = gradient16(width, height);
+        let dithered = dither_to_8bit(&input, width as usize, 1);
+        let rounded: Vec<u8> = input.iter().map(|&value| ((value as u32 * 255 + 32767) / 65535) as u8).collect();
+
+        // Every 8x8 block averages out to the 16-bit values it came from
+        for block_y in (0..height as usize).step_by(8) {
+            for block_x in (0..width as usize).step_by(8) {
+                let indices = || (block_y..block_y + 8).flat_map(|y| (block_x..block_x + 8).map(move |x| y * width as usize + x));
+                let expected = indices().map(|i| input[i] as f64 * 255.0 / 65535.0).sum::<f64>() / 64.0;
+                let actual = indices().map(|i| dithered[i] as f64).sum::<f64>() / 64.0;
+                assert!((actual - expected).abs() < 0.05, "block at {},{}: {} instead of {}", block_x, block_y, actual, expected);
+            }
+        }
+
+        // Rounding leaves a flat band per 8-bit level; dithering breaks them up
+        for (dithered, rounded) in dithered.chunks(width as usize).zip(rounded.chunks(width as usize)) {
+            assert!(longest_run(rounded) >= 100);
+            assert!(longest_run(dithered) * 2 <= longest_run(rounded), "{} vs {}", longest_run(dithered), longest_run(rounded));
+        }
+    }
+
+    // Bits per channel in an AVIF's pixi property: after the box type, version and flags, the channel count
+    fn avif_bit_depth(avif: &[u8]) -> u8 {
+        let pos = avif.windows(4).position(|window| window == b"pixi").unwrap();
+        avif[pos + 9]
+    }
+
+    #[test]
+    fn sixteen_bit_sources_become_10_bit_avif() {
+        let gradient = ImageBuffer::from_raw(64, 16, gradient16(64, 16).iter().flat_map(|&value| [value; 3]).collect()).unwrap();
+        let deep = DynamicImage::ImageRgb16(gradient);
+        let (avif, _) = encode_image(&deep, OutputFormat::Avif, 90).unwrap();
+        assert_eq!(avif_bit_depth(&avif), 10);
+
+        let (avif, _) = encode_image(&DynamicImage::ImageRgb8(deep.to_rgb8()), OutputFormat::Avif, 90).unwrap();
+        assert_eq!(avif_bit_depth(&avif), 8);
+    }
+
+    #[cfg(feature = "avif-decode")]
+    #[test]
+    fn ten_bit_avif_keeps_the_gradient() {
+        let gradient = ImageBuffer::from_raw(64, 16, gradient16(64, 16).iter().flat_map(|&value| [value; 3]).collect()).unwrap();
+        let (avif, _) = encode_image(&DynamicImage::ImageRgb16(gradient), OutputFormat::Avif, 90).unwrap();
+        let decoded = image::load_from_memory_with_format(&avif, ImageFormat::Avif).unwrap();
+        assert!(is_high_bit_depth(&decoded));
+        // Every 8 columns the source rises by half an 8-bit level, which 8 bits would round away
+        let decoded = decoded.to_rgb16();
+        let samples: Vec<u16> = (0..64).step_by(8).map(|x| decoded.get_pixel(x, 8)[0]).collect();
+        assert!(samples.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", samples);
+    }
+
+    #[test]
+    fn dithered_grayscale_follows_a_smooth_gradient() {
+        // Red climbs one level every 32 pixels, so exact luma rises by 0.299 per step
+        let (width, height) = (512, 16);
+        let ramp = DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, _| Rgb([100 + (x / 32) as u8, 120, 80])));
+        let weights = [299, 587, 114];
+        let exact = |x: u32| (299.0 * (100 + x / 32) as f64 + 587.0 * 120.0 + 114.0 * 80.0) / 1000.0;
+        let dithered = convert_to_grayscale_optimized(&ramp, weights, false, true).unwrap().to_luma8();
+        let plain = convert_to_grayscale_optimized(&ramp, weights, false, false).unwrap().to_luma8();
+
+        // Block means track the exact luma, where rounding is off by up to half a level
+        let block_error = |img: &image::GrayImage, block_x: u32, block_y: u32| {
+            let pixels = (block_y..block_y + 8).flat_map(|y| (block_x..block_x + 8).map(move |x| (x, y)));
+            pixels.map(|(x, y)| img.get_pixel(x, y)[0] as f64 - exact(x)).sum::<f64>().abs() / 64.0
+        };
+        let mut plain_worst: f64 = 0.0;
+        for block_y in (0..height).step_by(8) {
+            for block_x in (0..width).step_by(8) {
+                let error = block_error(&dithered, block_x, block_y);
+                assert!(error < 0.1, "block at {},{} is {} off", block_x, block_y, error);
+                plain_worst = plain_worst.max(block_error(&plain, block_x, block_y));
+            }
+        }
+        assert!(plain_worst > 0.3);
+
+        // And the flat bands rounding leaves are broken up
+        for (dithered, plain) in dithered.rows().zip(plain.rows()) {
+            let dithered: Vec<u8> = dithered.map(|pixel| pixel[0]).collect();
+            let plain: Vec<u8> = plain.map(|pixel| pixel[0]).collect();
+            assert!(longest_run(&dithered) < longest_run(&plain), "{} vs {}", longest_run(&dithered), longest_run(&plain));
+        }
+    }
+
+    // VP8X flags of a WebP encoded with an ICC profile, checking the profile was flagged
+    fn vp8x_flags(img: &DynamicImage, compression: Compression) -> u8 {
+        let (webp, _) = encode_image_with(img, OutputFormat::WebP, 80, compression, &EncoderSettings::default(), Some(b"profile")).unwrap();
+        assert_eq!(webp_chunks(&webp)[..2], [*b"VP8X", *b"ICCP"]);
+        assert_eq!(webp[20] & 0x20, 0x20);
+        webp[20]
+    }
+
+    #[test]
+    fn embedded_profiles_flag_alpha_only_when_the_webp_has_it() {
+        const ALPHA_FLAG: u8 = 0x10;
+        let opaque = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([10, 20, 30, 255])));
+        let clear = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, _| Rgba([10, 20, 30, if x < 4 { 0 } else { 255 }])));
+
+        // libwebp leaves a fully opaque alpha channel out of both kinds of file
+        assert_eq!(vp8x_flags(&opaque, Compression::Lossy) & ALPHA_FLAG, 0);
+        assert_eq!(vp8x_flags(&opaque, Compression::Lossless) & ALPHA_FLAG, 0);
+        // Lossless keeps real alpha in the VP8L header, lossy in an ALPH chunk of its own
+        assert_eq!(vp8x_flags(&clear, Compression::Lossless) & ALPHA_FLAG, ALPHA_FLAG);
+        assert_eq!(vp8x_flags(&clear, Compression::Lossy) & ALPHA_FLAG, ALPHA_FLAG);
+    }
+}